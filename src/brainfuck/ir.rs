@@ -0,0 +1,166 @@
+// An optimizing compilation pass that lowers the flat `Inst` tape into a
+// higher-level op list. This is opt-in (see `State::with_config_opt`): it
+// trades a compile step for much less per-instruction interpreter overhead
+// on loop-heavy programs, at the cost of being less obviously correct than
+// just walking `Inst`s one at a time.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{Data, DataPtr, Inst, InstPtr};
+
+// An index into the compiled op list, analogous to `InstPtr` for `Inst`.
+pub type OpPtr = usize;
+
+#[derive(Clone,Copy,Debug)]
+pub enum Op {
+	// Adds a run of `+`/`-` collapsed into a single delta.
+	Add(i32),
+	// Moves the data pointer by a run of `>`/`<` collapsed into a single delta.
+	Move(DataPtr),
+	// Sets the current cell to a fixed value. Used for clear loops (`[-]`).
+	Set(Data),
+	In,
+	Out,
+	// A `[>]`/`[<]` loop: advance the pointer by `step` until it lands on a
+	// zero cell.
+	ScanZero(DataPtr),
+	// Part of a multiply/copy loop: `cell[ptr + offset] += cell[ptr] * factor`.
+	// Always followed by a `Set(0)` that zeroes the originating cell.
+	AddMul(DataPtr, i32),
+	// Jump to `OpPtr` if the current cell is zero, else fall through.
+	Forward(OpPtr),
+	// Jump to `OpPtr` if the current cell is non-zero, else fall through.
+	Back(OpPtr),
+}
+
+/**
+ * Compiles a parsed instruction tape (plus its bracket jump table) into an
+ * optimized op list.
+ *
+ * @param insts			the parsed instruction tape
+ * @param jump_table	`insts`'s bracket jump table, as built by `State::parse_chars`
+ */
+pub fn compile(insts : &[Inst], jump_table : &[InstPtr]) -> Vec<Op> {
+	let mut ops = vec!();
+	compile_block(insts, jump_table, 0, insts.len(), &mut ops);
+	ops
+}
+
+fn compile_block(insts : &[Inst], jump_table : &[InstPtr], start : InstPtr, end : InstPtr, ops : &mut Vec<Op>) {
+	let mut i = start;
+	while i < end {
+		match insts[i] {
+			Inst::IncPtr | Inst::DecPtr => {
+				let mut delta : DataPtr = 0;
+				while i < end {
+					match insts[i] {
+						Inst::IncPtr => delta += 1,
+						Inst::DecPtr => delta -= 1,
+						_ => break,
+					}
+					i += 1;
+				}
+				ops.push(Op::Move(delta));
+			}
+			Inst::IncData | Inst::DecData => {
+				let mut delta : i32 = 0;
+				while i < end {
+					match insts[i] {
+						Inst::IncData => delta += 1,
+						Inst::DecData => delta -= 1,
+						_ => break,
+					}
+					i += 1;
+				}
+				ops.push(Op::Add(delta));
+			}
+			Inst::In => {
+				ops.push(Op::In);
+				i += 1;
+			}
+			Inst::Out => {
+				ops.push(Op::Out);
+				i += 1;
+			}
+			Inst::Null => {
+				i += 1;
+			}
+			// A loop: try to recognize it as one of the common idioms;
+			// fall back to an ordinary Forward/Back op pair otherwise.
+			Inst::Forward => {
+				let close = jump_table[i] - 1;
+				let body = &insts[i + 1..close];
+
+				if body.len() == 1 && body[0] == Inst::IncPtr {
+					ops.push(Op::ScanZero(1));
+				} else if body.len() == 1 && body[0] == Inst::DecPtr {
+					ops.push(Op::ScanZero(-1));
+				} else if body.len() == 1 && (body[0] == Inst::IncData || body[0] == Inst::DecData) {
+					// `[+]` and `[-]` both just run until the cell wraps
+					// to zero, regardless of which direction they step.
+					ops.push(Op::Set(0));
+				} else if let Some(muls) = multiply_loop(body) {
+					for (offset, factor) in muls {
+						ops.push(Op::AddMul(offset, factor));
+					}
+					ops.push(Op::Set(0));
+				} else {
+					let forward_pos = ops.len();
+					ops.push(Op::Forward(0)); // Patched below once `back_pos` is known.
+					compile_block(insts, jump_table, i + 1, close, ops);
+					let back_pos = ops.len();
+					ops.push(Op::Back(forward_pos + 1));
+					ops[forward_pos] = Op::Forward(back_pos + 1);
+				}
+
+				i = close + 1;
+			}
+			Inst::Back => unreachable!("jump_table guarantees every Back is only reached through its matching Forward"),
+		}
+	}
+}
+
+/**
+ * Recognizes a balanced loop of the form `[- >+>++<< ]`: only pointer and
+ * data instructions, net pointer movement of zero, and the cell at the
+ * loop's starting offset decrementing by exactly one per iteration. Such a
+ * loop just distributes multiples of its starting cell into others and
+ * then zeroes it, so it can run in O(1) instead of once per unit of the
+ * starting cell's value. Returns the `(offset, factor)` pairs for every
+ * other cell touched, or `None` if the loop doesn't match the idiom.
+ */
+fn multiply_loop(body : &[Inst]) -> Option<Vec<(DataPtr, i32)>> {
+	let mut ptr : DataPtr = 0;
+	let mut deltas : Vec<(DataPtr, i32)> = vec!();
+
+	for inst in body {
+		match *inst {
+			Inst::IncPtr => ptr += 1,
+			Inst::DecPtr => ptr -= 1,
+			Inst::IncData => add_delta(&mut deltas, ptr, 1),
+			Inst::DecData => add_delta(&mut deltas, ptr, -1),
+			_ => return None,
+		}
+	}
+
+	if ptr != 0 {
+		return None;
+	}
+
+	match deltas.iter().find(|&&(offset, _)| offset == 0) {
+		Some(&(_, -1)) => { }
+		_ => return None,
+	}
+
+	deltas.retain(|&(offset, _)| offset != 0);
+	deltas.sort_by_key(|&(offset, _)| offset);
+	Some(deltas)
+}
+
+fn add_delta(deltas : &mut Vec<(DataPtr, i32)>, offset : DataPtr, amount : i32) {
+	match deltas.iter_mut().find(|&&mut (off, _)| off == offset) {
+		Some(entry) => entry.1 += amount,
+		None => deltas.push((offset, amount)),
+	}
+}
@@ -1,18 +1,213 @@
-use std::collections::HashMap;
-use std::io::{stdin,stdout,Read,Write};
-use std::str::Chars;
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::Chars;
+#[cfg(feature = "std")]
+use std::io::{stdin,stdout,Stdin,Stdout};
+
+mod ir;
+use self::ir::Op;
+
+/// Errors that can occur while parsing or running a brainfuck program.
+#[derive(Debug)]
+pub enum BfError {
+	/// A `[` or `]` at `inst_ptr` has no matching bracket.
+	UnmatchedBracket { inst_ptr : InstPtr },
+	/// `>`/`<` (or a fused `Move`) pushed `data_ptr` past what `DataPtr` can
+	/// represent.
+	PointerOutOfBounds { inst_ptr : InstPtr },
+	/// A `,`/`.` failed to read from its `ByteIn`/write to its `ByteOut`.
+	#[cfg(feature = "std")]
+	Io(std::io::Error),
+	/// Same as above, but `std` isn't available to carry the underlying error.
+	#[cfg(not(feature = "std"))]
+	Io,
+}
+
+impl fmt::Display for BfError {
+	fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			BfError::UnmatchedBracket { inst_ptr } => {
+				write!(f, "unmatched bracket at instruction {}", inst_ptr)
+			}
+			BfError::PointerOutOfBounds { inst_ptr } => {
+				write!(f, "data pointer out of bounds at instruction {}", inst_ptr)
+			}
+			#[cfg(feature = "std")]
+			BfError::Io(ref e) => write!(f, "io error: {}", e),
+			#[cfg(not(feature = "std"))]
+			BfError::Io => write!(f, "io error"),
+		}
+	}
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for BfError {}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for BfError {
+	fn from(e : std::io::Error) -> Self {
+		BfError::Io(e)
+	}
+}
+
+/// A source of input bytes for the `,` instruction. Implemented for any
+/// `std::io::Read` when the `std` feature is on; `no_std` embedders
+/// implement it directly against whatever byte source they have.
+pub trait ByteIn {
+	/// Returns the next input byte, or `None` on end-of-input.
+	fn read_byte(&mut self) -> Result<Option<u8>, BfError>;
+}
+
+/// A sink for output bytes from the `.` instruction. Implemented for any
+/// `std::io::Write` when the `std` feature is on; `no_std` embedders
+/// implement it directly against whatever byte sink they have.
+pub trait ByteOut {
+	fn write_byte(&mut self, byte : u8) -> Result<(), BfError>;
+}
+
+#[cfg(feature = "std")]
+impl<T : std::io::Read> ByteIn for T {
+	fn read_byte(&mut self) -> Result<Option<u8>, BfError> {
+		let mut buf = [0; 1];
+		match self.read(&mut buf) {
+			Ok(1) => Ok(Some(buf[0])),
+			Ok(_) => Ok(None), // EOF
+			Err(e) => Err(BfError::Io(e)),
+		}
+	}
+}
+
+#[cfg(feature = "std")]
+impl<T : std::io::Write> ByteOut for T {
+	fn write_byte(&mut self, byte : u8) -> Result<(), BfError> {
+		match self.write(&[byte]) {
+			Ok(1) => Ok(()),
+			Ok(_) => Err(BfError::Io(std::io::Error::new(std::io::ErrorKind::WriteZero, "failed to write output byte"))),
+			Err(e) => Err(BfError::Io(e)),
+		}
+	}
+}
 
 // Used to represent data
 pub type Data = i64;
 
-// Used to represent a pointer to a memory cell on the data tape.
-pub type DataPtr = usize;
+/// The width (and wrapping behavior) of a data cell. Canonical Brainfuck
+/// uses 8-bit wrapping cells, but some programs are written against wider
+/// or unbounded cells, so this is a per-`State` choice rather than baked
+/// into `Data`.
+#[derive(Clone,Copy,Debug)]
+pub enum CellKind {
+	Wrapping8,
+	Wrapping16,
+	Wrapping32,
+	Unbounded,
+}
+
+impl CellKind {
+	fn inc(&self, data : Data) -> Data {
+		match *self {
+			CellKind::Wrapping8 => (data as u8).wrapping_add(1) as Data,
+			CellKind::Wrapping16 => (data as u16).wrapping_add(1) as Data,
+			CellKind::Wrapping32 => (data as u32).wrapping_add(1) as Data,
+			CellKind::Unbounded => data.wrapping_add(1),
+		}
+	}
+
+	fn dec(&self, data : Data) -> Data {
+		match *self {
+			CellKind::Wrapping8 => (data as u8).wrapping_sub(1) as Data,
+			CellKind::Wrapping16 => (data as u16).wrapping_sub(1) as Data,
+			CellKind::Wrapping32 => (data as u32).wrapping_sub(1) as Data,
+			CellKind::Unbounded => data.wrapping_sub(1),
+		}
+	}
+
+	// Like `inc`/`dec` repeated `delta` times, but in one step: used by the
+	// optimizer's fused `Add`/`AddMul` ops.
+	fn add(&self, data : Data, delta : Data) -> Data {
+		match *self {
+			CellKind::Wrapping8 => (data as u8).wrapping_add(delta as u8) as Data,
+			CellKind::Wrapping16 => (data as u16).wrapping_add(delta as u16) as Data,
+			CellKind::Wrapping32 => (data as u32).wrapping_add(delta as u32) as Data,
+			CellKind::Unbounded => data.wrapping_add(delta),
+		}
+	}
+}
+
+/// What a cell should become when `,` is executed at EOF. Real-world BF
+/// programs disagree on the convention, so this is configurable rather
+/// than the previously hardcoded `-1`.
+#[derive(Clone,Copy,Debug)]
+pub enum EofMode {
+	/// Leave the cell's current value untouched.
+	Unchanged,
+	/// Set the cell to 0.
+	Zero,
+	/// Set the cell to -1 (255 under `Wrapping8`).
+	NegOne,
+}
+
+// Used to represent a pointer to a memory cell on the data tape. Signed so
+// that `<` can walk left of the origin instead of underflowing.
+pub type DataPtr = isize;
 
 // Used to represent a pointer to an instruction on the instruction tape.
 pub type InstPtr = usize;
 
+// Number of cells per allocated chunk of the data tape.
+const CHUNK_SIZE : usize = 1024;
+
+// A sparse, chunked data tape. Cells are grouped into fixed-size chunks
+// that are allocated lazily on first write, giving O(1) array-indexed
+// access without the hashing overhead of a `HashMap` for every cell touch.
+// Non-negative and negative addresses are kept in separate chunk vectors
+// so the tape can extend in both directions from the origin.
+#[derive(Debug,Default)]
+struct DataTape {
+	pos : Vec<Option<Box<[Data; CHUNK_SIZE]>>>,
+	neg : Vec<Option<Box<[Data; CHUNK_SIZE]>>>,
+}
+
+impl DataTape {
+	fn new() -> Self {
+		DataTape { pos : vec!(), neg : vec!() }
+	}
+
+	// Splits a signed cell address into which side of the origin it's on
+	// and the (chunk, offset-within-chunk) indices into that side's chunks.
+	fn locate(ptr : DataPtr) -> (bool, usize, usize) {
+		if ptr >= 0 {
+			let idx = ptr as usize;
+			(false, idx / CHUNK_SIZE, idx % CHUNK_SIZE)
+		} else {
+			let idx = (-(ptr + 1)) as usize;
+			(true, idx / CHUNK_SIZE, idx % CHUNK_SIZE)
+		}
+	}
+
+	fn get(&self, ptr : DataPtr) -> Data {
+		let (neg, chunk_idx, cell_idx) = Self::locate(ptr);
+		let chunks = if neg { &self.neg } else { &self.pos };
+		chunks.get(chunk_idx)
+			.and_then(|chunk| chunk.as_ref())
+			.map_or(0, |chunk| chunk[cell_idx])
+	}
+
+	fn get_mut(&mut self, ptr : DataPtr) -> &mut Data {
+		let (neg, chunk_idx, cell_idx) = Self::locate(ptr);
+		let chunks = if neg { &mut self.neg } else { &mut self.pos };
+		if chunks.len() <= chunk_idx {
+			chunks.resize_with(chunk_idx + 1, Default::default);
+		}
+		let chunk = chunks[chunk_idx].get_or_insert_with(|| Box::new([0; CHUNK_SIZE]));
+		&mut chunk[cell_idx]
+	}
+}
+
 // Used to represent instructions in the instruction tape
-#[derive(Clone,Copy,Debug)]
+#[derive(Clone,Copy,Debug,PartialEq)]
 pub enum Inst {
 	IncPtr,
 	DecPtr,
@@ -25,103 +220,154 @@ pub enum Inst {
 	Null,
 }
 
-// Used to represent the state of a brainfuck machine
-#[derive(Debug,Default)]
-pub struct State {
+// Used to represent the state of a brainfuck machine. `R`/`W` are the
+// handles that the `,`/`.` instructions read from and write to, so the VM
+// can be embedded against anything that implements ByteIn/ByteOut rather
+// than only ever talking to the process's stdin/stdout.
+#[derive(Debug)]
+pub struct State<R : ByteIn, W : ByteOut> {
 	term : bool,
-	depth : usize,
 	data_ptr : DataPtr,
 	inst_ptr : InstPtr,
-	data_tape : HashMap<DataPtr, Data>,
+	data_tape : DataTape,
 	inst_tape : Vec<Inst>,
+	// Maps the index of a `[` to the index just past its matching `]` and
+	// vice versa, so `step` can jump in O(1) instead of rescanning the tape.
+	jump_table : Vec<InstPtr>,
+	input : R,
+	output : W,
+	cell_kind : CellKind,
+	eof_mode : EofMode,
+	// The optimized op list from `ir::compile`, and whether `step` should
+	// run it instead of walking `inst_tape` directly. Empty/false unless
+	// opted into via `with_config_opt`.
+	ops : Vec<Op>,
+	optimized : bool,
 }
 
-impl State {
-	pub fn from_chars(c : &mut Chars) -> Self {
+impl<R : ByteIn, W : ByteOut> State<R, W> {
+	/**
+	 * Builds a state from a program and the handles `,`/`.` should read
+	 * from and write to, using canonical 8-bit wrapping cells and the
+	 * `-1` EOF convention.
+	 *
+	 * @param program	the brainfuck source to parse
+	 * @param input		handle read from on `,`
+	 * @param output	handle written to on `.`
+	 */
+	pub fn with_io(program : &str, input : R, output : W) -> Result<Self, BfError> {
+		Self::with_config(program, input, output, CellKind::Wrapping8, EofMode::NegOne)
+	}
+
+	/**
+	 * Builds a state from a program, its I/O handles, and the cell width /
+	 * EOF behavior it should use.
+	 *
+	 * @param program	the brainfuck source to parse
+	 * @param input		handle read from on `,`
+	 * @param output	handle written to on `.`
+	 * @param cell_kind	the width and wrapping behavior of a data cell
+	 * @param eof_mode	what `,` stores in a cell when input is exhausted
+	 */
+	pub fn with_config(program : &str, input : R, output : W, cell_kind : CellKind, eof_mode : EofMode) -> Result<Self, BfError> {
+		Self::with_config_opt(program, input, output, cell_kind, eof_mode, false)
+	}
+
+	/**
+	 * Like `with_config`, but lets the caller opt into running the program
+	 * through the optimizing compiler (`ir::compile`) instead of walking
+	 * `inst_tape` one `Inst` at a time. The naive interpreter stays the
+	 * default so its behavior remains the reference to compare against.
+	 *
+	 * @param program	the brainfuck source to parse
+	 * @param input		handle read from on `,`
+	 * @param output	handle written to on `.`
+	 * @param cell_kind	the width and wrapping behavior of a data cell
+	 * @param eof_mode	what `,` stores in a cell when input is exhausted
+	 * @param optimize	if true, run the compiled op list instead of `inst_tape`
+	 */
+	pub fn with_config_opt(program : &str, input : R, output : W, cell_kind : CellKind, eof_mode : EofMode, optimize : bool) -> Result<Self, BfError> {
 		// Parse instructions into a list of enums representing instructions.
 		let mut inst_tape = vec!();
-		Self::parse_chars(c, &mut inst_tape);
-		
+		let mut jump_table = vec!();
+		Self::parse_chars(&mut program.chars(), &mut inst_tape, &mut jump_table)?;
+
+		let ops = if optimize {
+			ir::compile(&inst_tape, &jump_table)
+		} else {
+			vec!()
+		};
+
 		// Return the initialized state.
-		State {
+		Ok(State {
 			term : false,
-			depth : 0,
 			data_ptr : 0,
 			inst_ptr : 0,
-			data_tape : HashMap::new(),
+			data_tape : DataTape::new(),
 			inst_tape : inst_tape,
-		}
+			jump_table : jump_table,
+			input : input,
+			output : output,
+			cell_kind : cell_kind,
+			eof_mode : eof_mode,
+			ops : ops,
+			optimized : optimize,
+		})
 	}
-	
-	pub fn from_str(s : &str) -> Self {
-		Self::from_chars(&mut s.chars())
+
+	/**
+	 * Steps the state forward by executing the instruction (or, once
+	 * optimized, the op) currently at the instruction pointer.
+	 */
+	pub fn step(&mut self) -> Result<(), BfError> {
+		if self.optimized {
+			self.step_op()
+		} else {
+			self.step_inst()
+		}
 	}
-	
+
 	/**
-	 * Steps the state forward by executing the instruction currently at the 
-	 * instruction pointer.
+	 * Steps forward by interpreting `inst_tape` directly. This is the naive
+	 * path and stays available even once optimized, as the behavior the
+	 * optimizer is compiled against.
 	 */
-	pub fn step(&mut self) {
+	fn step_inst(&mut self) -> Result<(), BfError> {
 		match self.inst_tape.get(self.inst_ptr) {
 			// Increment the data pointer.
 			Some(&Inst::IncPtr) => {
-				self.data_ptr += 1;
+				self.data_ptr = self.data_ptr.checked_add(1)
+					.ok_or(BfError::PointerOutOfBounds { inst_ptr : self.inst_ptr })?;
 				self.inst_ptr += 1;
 			}
 			// Decrement the data pointer.
 			Some(&Inst::DecPtr) => {
-				self.data_ptr -= 1;
+				self.data_ptr = self.data_ptr.checked_sub(1)
+					.ok_or(BfError::PointerOutOfBounds { inst_ptr : self.inst_ptr })?;
 				self.inst_ptr += 1;
 			}
 			// Increment the data at the data pointer.
 			Some(&Inst::IncData) => {
 				let data_ptr = self.data_ptr;
-				{
-					let data = self.get_data_mut(&data_ptr);
-					*data += 1;
-				}
+				let data = self.get_data(&data_ptr);
+				*self.get_data_mut(&data_ptr) = self.cell_kind.inc(data);
 				self.inst_ptr += 1;
 			}
 			// Decrement the data at the data pointer.
 			Some(&Inst::DecData) => {
 				let data_ptr = self.data_ptr;
-				{
-					let data = self.get_data_mut(&data_ptr);
-					*data -= 1;
-				}
+				let data = self.get_data(&data_ptr);
+				*self.get_data_mut(&data_ptr) = self.cell_kind.dec(data);
 				self.inst_ptr += 1;
 			}
 			// Read input into the memory cell at the data pointer.
 			Some(&Inst::In) => {
-				let mut buf = [0;1];
-				let data_ptr = self.data_ptr;
-				{
-					let data = self.get_data_mut(&data_ptr);
-					*data = match stdin().read(&mut buf) {
-						Ok(1) => {
-							// Cast to u64 first so it doesn't sign extend.
-							buf[0] as u64 as Data
-						},
-						Ok(0) => { // EOF?
-						 	-1
-						}
-						_ => panic!("Read failed."),
-					}
-				}
+				self.do_in()?;
 				self.inst_ptr += 1;
 			}
 			// Print output from the memory cell at the data pointer.
 			Some(&Inst::Out) => {
-				let mut buf = [0;1];
-				let data_ptr = self.data_ptr;
-				{
-					let data = self.get_data(&data_ptr);
-					buf[0] = data as u8;
-				}
-				match stdout().write(&mut buf) {
-					Ok(1) => { },
-					_ => panic!("Write failed."),
-				}
+				self.do_out()?;
 				self.inst_ptr += 1;
 			}
 			// Jump forward.
@@ -130,13 +376,16 @@ impl State {
 				let jump = {
 					self.get_data(&data_ptr) == 0
 				};
-				
+
 				if jump {
-					self.inst_ptr = self.find_matching_rbrace();
+					// `jump_table` is only guaranteed to cover the tape
+					// `parse_chars` built it against; `get_inst_mut` can
+					// append instructions past that without updating it.
+					self.inst_ptr = *self.jump_table.get(self.inst_ptr)
+						.ok_or(BfError::UnmatchedBracket { inst_ptr : self.inst_ptr })?;
 				} else {
-					self.depth += 1;
+					self.inst_ptr += 1;
 				}
-				self.inst_ptr += 1;
 			}
 			// Jump backward.
 			Some(&Inst::Back) => {
@@ -144,13 +393,13 @@ impl State {
 				let jump = {
 					self.get_data(&data_ptr) != 0
 				};
-				
+
 				if jump {
-					self.inst_ptr = self.find_matching_lbrace();
+					self.inst_ptr = *self.jump_table.get(self.inst_ptr)
+						.ok_or(BfError::UnmatchedBracket { inst_ptr : self.inst_ptr })?;
 				} else {
-					self.depth -= 1;
+					self.inst_ptr += 1;
 				}
-				self.inst_ptr += 1;
 			}
 			// Handle instruction. (Just increment instruction pointer.
 			Some(&Inst::Null) => {
@@ -158,15 +407,115 @@ impl State {
 			}
 			None => { self.term = true; }
 		}
+
+		Ok(())
 	}
-	
+
+	/**
+	 * Steps forward by interpreting the compiled `ops` list built by
+	 * `ir::compile`.
+	 */
+	fn step_op(&mut self) -> Result<(), BfError> {
+		let op = match self.ops.get(self.inst_ptr) {
+			Some(&op) => op,
+			None => {
+				self.term = true;
+				return Ok(());
+			}
+		};
+
+		match op {
+			Op::Add(delta) => {
+				let data_ptr = self.data_ptr;
+				let data = self.get_data(&data_ptr);
+				*self.get_data_mut(&data_ptr) = self.cell_kind.add(data, delta as Data);
+				self.inst_ptr += 1;
+			}
+			Op::Move(delta) => {
+				self.data_ptr = self.data_ptr.checked_add(delta)
+					.ok_or(BfError::PointerOutOfBounds { inst_ptr : self.inst_ptr })?;
+				self.inst_ptr += 1;
+			}
+			Op::Set(value) => {
+				let data_ptr = self.data_ptr;
+				*self.get_data_mut(&data_ptr) = value;
+				self.inst_ptr += 1;
+			}
+			Op::In => {
+				self.do_in()?;
+				self.inst_ptr += 1;
+			}
+			Op::Out => {
+				self.do_out()?;
+				self.inst_ptr += 1;
+			}
+			Op::ScanZero(step) => {
+				while self.get_data(&self.data_ptr) != 0 {
+					self.data_ptr += step;
+				}
+				self.inst_ptr += 1;
+			}
+			Op::AddMul(offset, factor) => {
+				let src_ptr = self.data_ptr;
+				let src = self.get_data(&src_ptr);
+				let dst_ptr = src_ptr + offset;
+				let dst = self.get_data(&dst_ptr);
+				let product = src.wrapping_mul(factor as Data);
+				*self.get_data_mut(&dst_ptr) = self.cell_kind.add(dst, product);
+				self.inst_ptr += 1;
+			}
+			Op::Forward(target) => {
+				let jump = self.get_data(&self.data_ptr) == 0;
+				self.inst_ptr = if jump { target } else { self.inst_ptr + 1 };
+			}
+			Op::Back(target) => {
+				let jump = self.get_data(&self.data_ptr) != 0;
+				self.inst_ptr = if jump { target } else { self.inst_ptr + 1 };
+			}
+		}
+
+		Ok(())
+	}
+
+	// Reads one byte from `input` into the cell at the data pointer,
+	// applying `eof_mode` if input is exhausted. Shared by the naive and
+	// optimized interpreters.
+	fn do_in(&mut self) -> Result<(), BfError> {
+		let data_ptr = self.data_ptr;
+		match self.input.read_byte()? {
+			Some(byte) => {
+				// Cast to u64 first so it doesn't sign extend.
+				*self.get_data_mut(&data_ptr) = byte as u64 as Data;
+			}
+			None => { // EOF
+				match self.eof_mode {
+					EofMode::Unchanged => { }
+					EofMode::Zero => { *self.get_data_mut(&data_ptr) = 0; }
+					EofMode::NegOne => { *self.get_data_mut(&data_ptr) = self.cell_kind.dec(0); }
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	// Writes the cell at the data pointer to `output` as a single byte.
+	// Shared by the naive and optimized interpreters.
+	fn do_out(&mut self) -> Result<(), BfError> {
+		let data_ptr = self.data_ptr;
+		let data = self.get_data(&data_ptr);
+		self.output.write_byte(data as u8)
+	}
+
 	/**
 	 * Run until termination, i.e. the end of the data tape.
 	 */
-	pub fn run(&mut self) {
+	pub fn run(&mut self) -> Result<(), BfError> {
 		while !self.term {
-			self.step();
+			self.step()?;
 		}
+
+		Ok(())
 	}
 	
 	/**
@@ -175,20 +524,16 @@ impl State {
 	 * @param ptr	the address of the data cell
 	 */
 	pub fn get_data(&self, ptr : &DataPtr) -> Data {
-		if let Some(data) = self.data_tape.get(ptr) {
-			*data
-		} else {
-			0
-		}
+		self.data_tape.get(*ptr)
 	}
-	
+
 	/**
 	 * Get a mutable reference to a data cell.
 	 *
 	 * @param ptr	the address of the data cell
 	 */
 	pub fn get_data_mut(&mut self, ptr : &DataPtr) -> &mut Data {
-		self.data_tape.entry(*ptr).or_insert(0)
+		self.data_tape.get_mut(*ptr)
 	}
 	
 	/**
@@ -219,87 +564,182 @@ impl State {
 	}
 	
 	/**
-	 * Parses a (potentially partial) script, appending instructions to inst_tape.
+	 * Parses a (potentially partial) script, appending instructions to
+	 * inst_tape and filling in jump_table so that each `[`/`]` pair knows
+	 * where to jump without having to rescan the tape at run time.
 	 */
-	fn parse_chars(c : &mut Chars, inst_tape : &mut Vec<Inst>) {
+	fn parse_chars(c : &mut Chars, inst_tape : &mut Vec<Inst>, jump_table : &mut Vec<InstPtr>) -> Result<(), BfError> {
+		// Indices of `[`s seen so far that haven't been matched yet.
+		let mut open_stack = vec!();
+
 		// Parse instructions into a list of enums representing instructions.
-		for inst in c.filter_map(|c| {
+		for inst in c.map(|c| {
 			match c {
-				'>' => Some(Inst::IncPtr),
-				'<' => Some(Inst::DecPtr),
-				'+' => Some(Inst::IncData),
-				'-' => Some(Inst::DecData),
-				',' => Some(Inst::In),
-				'.' => Some(Inst::Out),
-				'[' => Some(Inst::Forward),
-				']' => Some(Inst::Back),
-				_ => Some(Inst::Null) // BF skips non-instruction char's.
+				'>' => Inst::IncPtr,
+				'<' => Inst::DecPtr,
+				'+' => Inst::IncData,
+				'-' => Inst::DecData,
+				',' => Inst::In,
+				'.' => Inst::Out,
+				'[' => Inst::Forward,
+				']' => Inst::Back,
+				_ => Inst::Null // BF skips non-instruction char's.
 			}
 		}) {
+			let idx = inst_tape.len();
 			inst_tape.push(inst);
-		}
-	}
-	
-	/**
-	 * Finds the matching [ to the current ].
-	 */
-	fn find_matching_lbrace(&self) -> InstPtr {
-		let mut cur_depth = self.depth;
-		let mut i = self.inst_ptr;
-		let mut found = false;
-		while i > 0 && !found {
-			i -= 1;
-			match self.inst_tape.get(i) {
-				Some(&Inst::Forward) => {
-					if cur_depth == self.depth {
-						found = true;
-					} else {
-						cur_depth -= 1;
-					}
-				}
-				Some(&Inst::Back) => {
-					cur_depth += 1;
-				}
-				_ => { }
-			}
-		}
-		
-		if found {
-			i
-		} else {
-			panic!("Unmatched braces.");
-		}
-	}
-	
-	
-	/**
-	 * Finds the matching ] to the current [.
-	 */
-	fn find_matching_rbrace(&self) -> InstPtr {
-		let mut cur_depth = self.depth;
-		let mut i = self.inst_ptr;
-		let mut found = false;
-		while i > 0 && !found {
-			i += 1;
-			match self.inst_tape.get(i) {
-				Some(&Inst::Forward) => {
-					cur_depth += 1;
+			jump_table.push(0);
+
+			match inst {
+				Inst::Forward => {
+					open_stack.push(idx);
 				}
-				Some(&Inst::Back) => {
-					if cur_depth == self.depth {
-						found = true;
-					} else {
-						cur_depth -= 1;
+				Inst::Back => {
+					match open_stack.pop() {
+						Some(open_idx) => {
+							jump_table[open_idx] = idx + 1;
+							jump_table[idx] = open_idx + 1;
+						}
+						None => return Err(BfError::UnmatchedBracket { inst_ptr : idx }),
 					}
 				}
 				_ => { }
 			}
 		}
-		
-		if found {
-			i
-		} else {
-			panic!("Unmatched braces.");
+
+		if let Some(&inst_ptr) = open_stack.first() {
+			return Err(BfError::UnmatchedBracket { inst_ptr });
 		}
+
+		Ok(())
+	}
+}
+
+// Convenience constructors for the common case of talking to the process's
+// actual stdin/stdout, so existing callers don't have to name `with_io`.
+// Only available with the `std` feature, since `Stdin`/`Stdout` are.
+#[cfg(feature = "std")]
+impl State<Stdin, Stdout> {
+	pub fn from_chars(c : &mut Chars) -> Result<Self, BfError> {
+		Self::from_str(c.as_str())
+	}
+
+	pub fn from_str(s : &str) -> Result<Self, BfError> {
+		Self::with_io(s, stdin(), stdout())
+	}
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+	use super::*;
+
+	// Runs `program` against `input` with both the naive and optimized
+	// interpreters and asserts they produce identical output, returning
+	// that output so callers can assert on it too.
+	fn run_both(program : &str, input : &[u8]) -> Vec<u8> {
+		let mut naive_out = vec!();
+		State::with_config_opt(program, input, &mut naive_out, CellKind::Wrapping8, EofMode::NegOne, false)
+			.unwrap().run().unwrap();
+
+		let mut opt_out = vec!();
+		State::with_config_opt(program, input, &mut opt_out, CellKind::Wrapping8, EofMode::NegOne, true)
+			.unwrap().run().unwrap();
+
+		assert_eq!(naive_out, opt_out, "naive and optimized interpreters diverged for {:?}", program);
+		naive_out
+	}
+
+	#[test]
+	fn echoes_input_through_with_io() {
+		let out = run_both(",.,.,.", b"abc");
+		assert_eq!(out, b"abc");
+	}
+
+	#[test]
+	fn eof_mode_neg_one_stores_255() {
+		let mut out = vec!();
+		State::with_config(",.", &b""[..], &mut out, CellKind::Wrapping8, EofMode::NegOne)
+			.unwrap().run().unwrap();
+		assert_eq!(out, vec![255]);
+	}
+
+	#[test]
+	fn eof_mode_zero_stores_0() {
+		let mut out = vec!();
+		State::with_config(",.", &b""[..], &mut out, CellKind::Wrapping8, EofMode::Zero)
+			.unwrap().run().unwrap();
+		assert_eq!(out, vec![0]);
+	}
+
+	#[test]
+	fn eof_mode_unchanged_leaves_cell_alone() {
+		// +++++ leaves the cell at 5 before `,` hits EOF and `.` prints it.
+		let mut out = vec!();
+		State::with_config("+++++,.", &b""[..], &mut out, CellKind::Wrapping8, EofMode::Unchanged)
+			.unwrap().run().unwrap();
+		assert_eq!(out, vec![5]);
+	}
+
+	#[test]
+	fn clear_loop_dash_and_plus_agree() {
+		assert_eq!(run_both("+++++[-].", b""), vec![0]);
+		assert_eq!(run_both("+++++[+].", b""), vec![0]);
+	}
+
+	#[test]
+	fn scan_zero_loop() {
+		// Two cells set, then `[<]` scans left until it finds the zero cell
+		// at the origin.
+		assert_eq!(run_both(">+>+[<]>.", b""), vec![1]);
+	}
+
+	#[test]
+	fn multiply_loop() {
+		// cell[0] = 5; cell[0]*3 accumulates into cell[1] via `[->+++<]`.
+		assert_eq!(run_both("+++++[->+++<]>.", b""), vec![15]);
+	}
+
+	#[test]
+	fn negative_cell_addressing() {
+		// Write to cell -1, read it back, and confirm the origin (cell 0)
+		// is untouched.
+		assert_eq!(run_both("<+.>.", b""), vec![1, 0]);
+	}
+
+	#[test]
+	fn negative_cell_addressing_crosses_chunk_boundary() {
+		// Walk past the first chunk on the negative side so this actually
+		// exercises `DataTape::locate`'s chunk/cell-index split, not just
+		// chunk 0.
+		let there = "<".repeat(CHUNK_SIZE + 1);
+		let back = ">".repeat(CHUNK_SIZE + 1);
+		let program = format!("{}+.{}.", there, back);
+		assert_eq!(run_both(&program, b""), vec![1, 0]);
+	}
+
+	#[test]
+	fn unmatched_open_bracket_errors() {
+		assert!(matches!(
+			State::with_io("[", &b""[..], vec!()),
+			Err(BfError::UnmatchedBracket { inst_ptr : 0 })
+		));
+	}
+
+	#[test]
+	fn unmatched_close_bracket_errors() {
+		assert!(matches!(
+			State::with_io("]", &b""[..], vec!()),
+			Err(BfError::UnmatchedBracket { inst_ptr : 0 })
+		));
+	}
+
+	#[test]
+	fn pointer_out_of_bounds_errors_instead_of_panicking() {
+		let mut state = State::with_io(">.", &b""[..], vec!()).unwrap();
+		state.data_ptr = isize::MAX;
+		assert!(matches!(
+			state.step(),
+			Err(BfError::PointerOutOfBounds { inst_ptr : 0 })
+		));
 	}
 }
@@ -0,0 +1,12 @@
+//! Core brainfuck VM, usable without `std` (see the `std` feature) so it
+//! can be embedded in bare-metal or WASM targets that only have `alloc`.
+#![cfg_attr(not(feature = "std"), no_std)]
+// These reflect conventions already established before `clippy` could be run
+// against this crate (there was no Cargo.toml): tab-indented `/** */` doc
+// comments, explicit `field : field` struct init, and a `from_str` that
+// predates (and isn't meant to implement) `std::str::FromStr`.
+#![allow(clippy::tabs_in_doc_comments, clippy::redundant_field_names, clippy::should_implement_trait)]
+
+extern crate alloc;
+
+pub mod brainfuck;
@@ -1,9 +1,8 @@
-mod brainfuck;
-
-use brainfuck::State;
+use rust_brainfuck::brainfuck::State;
 
 fn main() {
 	let mut state = State::from_str("++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>\
-		+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.");
-	state.run();
+		+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.")
+		.expect("failed to parse program");
+	state.run().expect("failed to run program");
 }